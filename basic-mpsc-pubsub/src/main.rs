@@ -4,58 +4,208 @@
 // This is rougly based on ROS Pub/Sub, after what was explored earlier.
 
 // Notes:
-// - Exclusively reactive modules based on message handlers alone might not be the way forward, there is also need for active loops and checks.
-// - This implementation currently still misses a timer tick.
 // - The M in MPSC is not particularly useful, since the broker might also want to send messages to a single module.
 
 use {
     std::{
-        time::Duration,
+        time::{
+            Duration,
+            Instant,
+        },
         thread,
-        sync::mpsc::{
-            Sender,
-            Receiver,
-            channel,
+        sync::{
+            Arc,
+            atomic::{
+                AtomicBool,
+                AtomicU64,
+                AtomicUsize,
+                Ordering,
+            },
+            mpsc::{
+                Sender,
+                Receiver,
+                TryRecvError,
+                channel,
+            },
+        },
+        collections::{
+            HashMap,
+            HashSet,
+            BinaryHeap,
+        },
+        cell::{
+            RefCell,
+            Cell,
+        },
+        cmp::Reverse,
+        any::Any,
+        fmt,
+        io::{
+            self,
+            Read,
+            Write,
+            ErrorKind,
+        },
+        net::{
+            TcpStream,
+            SocketAddr,
         },
-        collections::HashSet,
-        cell::RefCell,
     }
 };
 
-// The generic handler type
-type Handler = fn(InMessage,&Sender<OutMessage>);
+// The generic handler type for a reactive module: called once per incoming message
+type Handler = fn(InMessage,&Sender<OutMessage>) -> Result<(),Error>;
+
+// The loop type for an active module: owns its thread's main loop entirely (e.g. a blocking
+// camera/RTSP read), and is expected to poll `in_rx` for InMessage::Shutdown between iterations
+// of whatever blocking work it does, rather than ever calling `in_rx.recv()`.
+type ActiveLoop = fn(&Receiver<InMessage>,&Sender<OutMessage>) -> Result<(),Error>;
+
+// A module-level error, e.g. "camera hardware failed to initialize". Lifecycle hooks and
+// message handlers return this instead of panicking through `.expect()`, so the broker can log
+// the failure and shut the module down cleanly.
+#[derive(Debug)]
+pub struct Error(String);
+
+impl Error {
+    fn new(message: impl Into<String>) -> Error {
+        Error(message.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"{}",self.0)
+    }
+}
+
+impl std::error::Error for Error { }
 
-// These are messages to communicate between modules
+// Forwards an OutMessage to the broker, turning a disconnected channel into an `Error` instead
+// of panicking.
+fn send(out_tx: &Sender<OutMessage>,message: OutMessage) -> Result<(),Error> {
+    out_tx.send(message).map_err(|_| Error::new("broker is gone, failed to send OutMessage"))
+}
+
+// A type-erased message payload. Modules publish and receive these by topic and downcast to
+// whatever concrete type they expect, so third-party modules can define their own payload
+// structs without ever touching this file.
+type Payload = Arc<dyn Any + Send + Sync>;
+
+// Hands out the correlation IDs that pair up a request with its reply.
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(0);
+
+// How long the broker waits for a reply before giving up on a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How many subscribers the camera currently believes '/frames' has; starts optimistic, updated
+// by InMessage::SubscriberCountChanged.
+static FRAME_SUBSCRIBER_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+// Whether the camera has already published its one-off status report.
+static CAMERA_STATUS_PUBLISHED: AtomicBool = AtomicBool::new(false);
+
+// A parsed subscription pattern, ROS/riker-style: a topic split into '/'-separated segments,
+// where a "*" segment matches exactly one concrete segment and a trailing "**" segment matches
+// zero or more trailing segments.
 #[derive(Clone)]
-pub enum AppMessage {
-    VideoFrame,  // hypothetical video frame
-    FaceCoords,  // hypothetical face coordinates
+struct TopicPattern {
+    segments: Vec<String>,
+}
+
+impl TopicPattern {
+
+    fn parse(pattern: &str) -> TopicPattern {
+        TopicPattern {
+            segments: pattern.split('/').map(|segment| segment.to_string()).collect(),
+        }
+    }
+
+    fn matches(&self,topic: &str) -> bool {
+        let topic_segments: Vec<&str> = topic.split('/').collect();
+        Self::match_segments(&self.segments,&topic_segments)
+    }
+
+    fn match_segments(pattern: &[String],topic: &[&str]) -> bool {
+        match pattern.first() {
+
+            // pattern exhausted, only matches if the topic is too
+            None => topic.is_empty(),
+
+            // "**" greedily matches everything that's left, however many segments remain
+            Some(head) if head == "**" => true,
+
+            // "*" matches any single segment, anything else must match exactly
+            Some(head) => match topic.first() {
+                None => false,
+                Some(topic_head) => {
+                    (head == "*" || head == topic_head) &&
+                    Self::match_segments(&pattern[1..],&topic[1..])
+                },
+            },
+        }
+    }
+}
+
+// Example payload types a couple of test modules publish. Any crate can define its own such
+// structs; the broker never needs to know about them, it only ever sees `Payload`.
+pub struct VideoFrame;     // hypothetical video frame
+pub struct FaceCoords;     // hypothetical face coordinates
+pub struct CameraStatus;   // hypothetical camera status report
+
+// Reserved topic the broker republishes to when a `Generic` message had zero subscribers.
+pub const DEAD_LETTER_TOPIC: &str = "/dead-letter";
+
+// The payload carried on `DEAD_LETTER_TOPIC`, wrapping whatever went undelivered.
+pub struct DeadLetter {
+    pub topic: String,
+    pub payload: Payload,
 }
 
 // These are all possible messages into the module
 pub enum InMessage {
-    Startup,                     // initialize the module
-    Shutdown,                    // shut down the module
-    Tick,                        // timer tick signal (just for this example)
-    Subscribed(String),          // indication that the module successfully subscribed to a topic
-    Unsubscribed(String),        // indicating that the module successfully unsubscribed from a topic
-    Generic(String,AppMessage),  // generic message from specific topic
+    Startup,                       // initialize the module
+    Shutdown,                      // shut down the module
+    Tick,                          // timer tick signal (just for this example)
+    Subscribed(String),            // indication that the module successfully subscribed to a topic
+    Unsubscribed(String),          // indicating that the module successfully unsubscribed from a topic
+    Generic(String,Payload),       // generic message from specific topic
+    Request(String,Payload,u64),          // an incoming service call, reply with OutMessage::Reply(correlation_id,..)
+    Reply(u64,Payload),                   // the answer to one of this module's own requests
+    RequestTimedOut(u64),                 // none of this module's own requests got a reply in time
+    SubscriberCountChanged(String,usize),  // the number of subscribers on a topic this module cares about just changed
 }
 
 // These are all possible messages out from the module, the API of the broker as seen by the module
 pub enum OutMessage {
-    Subscribe(String),           // "subscribe me to a topic"
-    Unsubscribe(String),         // "unsubscribe me from a topic"
-    Generic(String,AppMessage),  // "send AppMessage to a topic"
+    Subscribe(String),              // "subscribe me to a topic"
+    SubscribeLatest(String),        // "subscribe me to a topic, but only ever keep the newest undelivered message"
+    Unsubscribe(String),            // "unsubscribe me from a topic"
+    Generic(String,Payload),        // "send this payload to a topic"
+    SetTimer(Duration),             // "tick me at this rate from now on"
+    Request(String,Payload,u64),    // "call whoever serves this topic, tag the call with correlation_id"
+    Reply(u64,Payload),             // "here's my answer to that correlation_id"
+    Failed(String),                 // "one of my lifecycle hooks returned an Err, here's why"
+}
+
+// A single subscription: the pattern it was subscribed with, and its delivery policy
+#[derive(Clone)]
+struct Subscription {
+    pattern: TopicPattern,
+    conflated: bool,  // if true, only the latest undelivered message for a topic is kept
 }
 
 // A module
 pub struct Module {
 #[allow(dead_code)]
-    name: String,                             // name of the module
-    subscriptions: RefCell<HashSet<String>>,  // topics this module receiving from
-    in_tx: Sender<InMessage>,                 // broker-side sender to this module
-    out_rx: Receiver<OutMessage>,             // broker-side receiver from this module
+    name: String,                                         // name of the module
+    subscriptions: RefCell<HashMap<String,Subscription>>,  // pattern string -> subscription, for topics this module receives from
+    in_tx: Sender<InMessage>,                  // broker-side sender to this module
+    out_rx: Receiver<OutMessage>,              // broker-side receiver from this module
+    tick_interval: RefCell<Option<Duration>>,  // current tick rate, if this module asked for one
+    tick_generation: Cell<u64>,                // bumped on every SetTimer, so stale heap entries from an earlier SetTimer are recognized and dropped on pop
+    ready: Arc<AtomicBool>,                    // true while the module's thread is blocked waiting for its next message
+    handle: RefCell<Option<thread::JoinHandle<()>>>,  // taken and joined during broker shutdown
 }
 
 // The broker owns the modules, so this API is from the broker's point of view
@@ -73,157 +223,516 @@ impl Module {
         // because of move semantics
         let local_name = name.to_string();
 
+        // true whenever the module is blocked in recv(), i.e. ready for its next message
+        let ready = Arc::new(AtomicBool::new(true));
+        let local_ready = ready.clone();
+
         // start thread, pass incoming messages to the handler
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
 
             println!("started thread for module '{}'",local_name);
 
             // blocking wait for a message
             while let Ok(in_message) = in_rx.recv() {
 
-                // and execute it
-                handler(in_message,&out_tx);
+                let is_shutdown = matches!(in_message,InMessage::Shutdown);
+
+                // no longer ready while the handler is running
+                local_ready.store(false,Ordering::SeqCst);
+
+                // and execute it; a failing hook doesn't panic - it reports back to the broker
+                // via OutMessage::Failed so the broker (not just this thread's own stdout) learns
+                // about it and can act, e.g. by tearing the module down
+                if let Err(err) = handler(in_message,&out_tx) {
+                    println!("module '{}': {}",local_name,err);
+                    let _ = out_tx.send(OutMessage::Failed(err.to_string()));
+                }
+
+                // back to blocking on the next recv()
+                local_ready.store(true,Ordering::SeqCst);
+
+                // the broker told us to shut down, so this is our last message
+                if is_shutdown {
+                    break;
+                }
             }
         });
 
         // return the running module to the broker
         Module {
             name: name.to_string(),
-            subscriptions: RefCell::new(HashSet::new()),
+            subscriptions: RefCell::new(HashMap::new()),
+            in_tx: in_tx,
+            out_rx: out_rx,
+            tick_interval: RefCell::new(None),
+            tick_generation: Cell::new(0),
+            ready: ready,
+            handle: RefCell::new(Some(handle)),
+        }
+    }
+
+    // An active module owns its thread's entire main loop (e.g. a blocking camera/RTSP read)
+    // instead of being driven message-by-message; it polls `in_rx` itself for InMessage::Shutdown.
+    fn new_active(name: &str,active_loop: ActiveLoop) -> Module {
+
+        println!("Creating active module '{}'",name);
+
+        let (in_tx,in_rx) = channel::<InMessage>();
+        let (out_tx,out_rx) = channel::<OutMessage>();
+        let local_name = name.to_string();
+
+        let handle = thread::spawn(move || {
+
+            println!("started thread for active module '{}'",local_name);
+
+            if let Err(err) = active_loop(&in_rx,&out_tx) {
+                println!("active module '{}': {}",local_name,err);
+                let _ = out_tx.send(OutMessage::Failed(err.to_string()));
+            }
+        });
+
+        Module {
+            name: name.to_string(),
+            subscriptions: RefCell::new(HashMap::new()),
             in_tx: in_tx,
             out_rx: out_rx,
-        }    
+            tick_interval: RefCell::new(None),
+            tick_generation: Cell::new(0),
+            ready: Arc::new(AtomicBool::new(true)),
+            handle: RefCell::new(Some(handle)),
+        }
+    }
+}
+
+// Sends a message from the broker to one module, logging instead of panicking if that module's
+// thread has already exited (e.g. an active module whose fallible Startup/main-loop returned an
+// Err) rather than taking the whole broker down over one module that's gone.
+fn notify(target: &Sender<InMessage>,name: &str,message: InMessage) {
+    if target.send(message).is_err() {
+        println!("broker: module '{}' is no longer reachable, dropping a message to it",name);
+    }
+}
+
+// Delivers `payload` to every module subscribed to `topic`, honoring each subscriber's
+// conflation policy. Returns whether at least one subscriber received it, so the caller can
+// dead-letter it otherwise.
+fn route(modules: &[Module],latest_mailbox: &mut HashMap<(usize,String),Payload>,topic: &str,payload: &Payload) -> bool {
+
+    let mut delivered = false;
+
+    for (target_index,target) in modules.iter().enumerate() {
+
+        let conflated = {
+            let subs = target.subscriptions.borrow();
+            let matching: Vec<&Subscription> = subs.values().filter(|s| s.pattern.matches(topic)).collect();
+            if matching.is_empty() {
+                continue;
+            }
+            matching.iter().any(|s| s.conflated)
+        };
+
+        delivered = true;
+        if conflated {
+            latest_mailbox.insert((target_index,topic.to_string()),payload.clone());
+        } else {
+            notify(&target.in_tx,&target.name,InMessage::Generic(topic.to_string(),payload.clone()));
+        }
     }
+
+    delivered
 }
 
 // A few test module handlers:
 
 // The camera module reads camera hardware (here triggered by a timer)
-fn camera_handler(in_message: InMessage,out_tx: &Sender<OutMessage>) {
+fn camera_handler(in_message: InMessage,out_tx: &Sender<OutMessage>) -> Result<(),Error> {
 
     match in_message {
 
         // camera does startup, initialize hardware, etc.
         InMessage::Startup => {
             println!("camera: Startup");
+            println!("camera: Requesting a tick every 33ms (roughly 30fps)");
+            send(out_tx,OutMessage::SetTimer(Duration::from_millis(33)))?;
+            Ok(())
         },
 
         // camera does shutdown
         InMessage::Shutdown => {
             println!("camera: Shutdown");
+            Ok(())
         },
 
         // camera receives a timer tick
         InMessage::Tick => {
-            println!("camera: Tick");
-            println!("camera: Reading the camera hardware and publishing the video frame.");
-            out_tx.send(
-                OutMessage::Generic("/frames".to_string(),AppMessage::VideoFrame)
-            ).expect("out_tx.send() failed!");
+
+            // publish a status report to a topic nobody subscribes to, on purpose, to
+            // demonstrate dead-letter routing (delayed past startup so it isn't a race
+            // against everyone else's subscriptions)
+            if !CAMERA_STATUS_PUBLISHED.swap(true,Ordering::SeqCst) {
+                println!("camera: Publishing status to '/camera/status' (nobody subscribes there)");
+                send(out_tx,OutMessage::Generic("/camera/status".to_string(),Arc::new(CameraStatus)))?;
+            }
+
+            if FRAME_SUBSCRIBER_COUNT.load(Ordering::SeqCst) == 0 {
+                println!("camera: Tick, but nobody is listening on '/frames' - not reading the hardware");
+            } else {
+                println!("camera: Tick");
+                println!("camera: Reading the camera hardware and publishing the video frame.");
+                send(out_tx,OutMessage::Generic("/frames".to_string(),Arc::new(VideoFrame)))?;
+            }
+            Ok(())
+        },
+
+        // the broker told us how many subscribers '/frames' currently has
+        InMessage::SubscriberCountChanged(topic,count) if topic == "/frames" => {
+            println!("camera: '/frames' now has {} subscriber(s)",count);
+            FRAME_SUBSCRIBER_COUNT.store(count,Ordering::SeqCst);
+            Ok(())
         },
 
         // everything else camera doesn't care about
-        _ => { },
+        _ => Ok(()),
     }
 }
 
-fn face_detector_handler(in_message: InMessage,out_tx: &Sender<OutMessage>) {
-    
+fn face_detector_handler(in_message: InMessage,out_tx: &Sender<OutMessage>) -> Result<(),Error> {
+
     match in_message {
 
         // face detector does startup, subscribe to the video topic
         InMessage::Startup => {
             println!("face_detector: Startup");
-            println!("face_detector: Subscribing to '/frames'");
-            out_tx.send(
-                OutMessage::Subscribe("/frames".to_string())
-            ).expect("face_detector: failed to subscribe to '/frames'.");
+            println!("face_detector: Subscribing to '/frames' (keep-latest, so a slow detector never lags behind on stale frames)");
+            send(out_tx,OutMessage::SubscribeLatest("/frames".to_string()))?;
+            println!("face_detector: Subscribing to '/face_detector/query' to serve requests for the current result");
+            send(out_tx,OutMessage::Subscribe("/face_detector/query".to_string()))?;
+            Ok(())
         },
 
         // face detector does shutdown
         InMessage::Shutdown => {
             println!("face_detector: Shutdown");
+            Ok(())
         },
 
         // face detector wants to know when a subscription starts
         InMessage::Subscribed(topic) => {
             println!("face_detector: Broker notified me that I'm now subscribed to '{}'",topic);
+            Ok(())
         },
 
         // face detector wants to know when a subscription ends
         InMessage::Unsubscribed(topic) => {
             println!("face_detector: Broker notified me that I've unsubscribed from '{}'",topic);
+            Ok(())
         },
 
-        // face detector gets an AppMessage
-        InMessage::Generic(topic,app_message) => {
-
-            match app_message {
-
-                // face detector receives video frame
-                AppMessage::VideoFrame => {
-                    println!("face_detector: Received video frame from '{}'",topic);
-                    println!("face_detector: Detecting face");
-                    out_tx.send(
-                        OutMessage::Generic("/faces".to_string(),AppMessage::VideoFrame)
-                    ).expect("face_detector: failed to send video frame!");
-                },
+        // face detector gets a payload, downcast to whatever concrete type it expects
+        InMessage::Generic(topic,payload) => {
 
-                // and nothing else
-                _ => { },
+            // face detector receives video frame
+            if payload.downcast_ref::<VideoFrame>().is_some() {
+                println!("face_detector: Received video frame from '{}'",topic);
+                println!("face_detector: Detecting face");
+                send(out_tx,OutMessage::Generic("/faces".to_string(),Arc::new(FaceCoords)))?;
             }
+            Ok(())
+        },
+
+        // someone wants the current face detection result synchronously
+        InMessage::Request(topic,_payload,correlation_id) => {
+            println!("face_detector: Serving request on '{}'",topic);
+            send(out_tx,OutMessage::Reply(correlation_id,Arc::new(FaceCoords)))?;
+            Ok(())
         },
 
         // and nothing else
-        _ => { },
+        _ => Ok(()),
     }
 }
 
-fn display_handler(in_message: InMessage,out_tx: &Sender<OutMessage>) {
-    
+fn display_handler(in_message: InMessage,out_tx: &Sender<OutMessage>) -> Result<(),Error> {
+
     match in_message {
 
         // display does startup, subscribes to face coordinate topic
         InMessage::Startup => {
             println!("display: Startup");
-            println!("display: Subscribing to '/faces'");
-            out_tx.send(
-                OutMessage::Subscribe("/faces".to_string())
-            ).expect("display: failed to subscribe to '/faces'");
+            println!("display: Subscribing to '/faces' (keep-latest, so the display always shows the newest result)");
+            send(out_tx,OutMessage::SubscribeLatest("/faces".to_string()))?;
+
+            // also demonstrate the request/reply ("ask") path: query the face detector directly
+            // for its current result instead of waiting for the next broadcast
+            let correlation_id = NEXT_CORRELATION_ID.fetch_add(1,Ordering::SeqCst);
+            println!("display: Asking '/face_detector/query' for the current result (correlation_id {})",correlation_id);
+            send(out_tx,OutMessage::Request("/face_detector/query".to_string(),Arc::new(()),correlation_id))?;
+            Ok(())
         },
 
         // display does shutdown
         InMessage::Shutdown => {
             println!("display: Shutdown");
+            Ok(())
         },
 
         // display wants to know when a subscription starts
         InMessage::Subscribed(topic) => {
             println!("display: Broker notified me that I'm now subscribed to '{}'",topic);
+            Ok(())
         },
 
         // display wants to know when a subscription ends
         InMessage::Unsubscribed(topic) => {
             println!("display: Broker notified me that I've unsubscribed from '{}'",topic);
+            Ok(())
         },
 
-        // display gets AppMessage
-        InMessage::Generic(topic,app_message) => {
+        // display gets a payload, downcast to whatever concrete type it expects
+        InMessage::Generic(topic,payload) => {
 
-            match app_message {
+            // new face coordinates
+            if payload.downcast_ref::<FaceCoords>().is_some() {
+                println!("display: Received face coordinates from '{}'",topic);
+            }
+            Ok(())
+        },
 
-                // new face coordinates
-                AppMessage::FaceCoords => {
-                    println!("display: Received face coordinates from '{}'",topic);
-                },
+        // got the answer to one of our own requests
+        InMessage::Reply(correlation_id,_payload) => {
+            println!("display: Got a reply for request {}",correlation_id);
+            Ok(())
+        },
+
+        // one of our own requests went unanswered
+        InMessage::RequestTimedOut(correlation_id) => {
+            println!("display: Request {} timed out with no reply",correlation_id);
+            Ok(())
+        },
+
+        // and nothing else
+        _ => Ok(()),
+    }
+}
+
+// The logger subscribes to the frame/face pipeline via "/**" wildcards, so it needs no
+// knowledge of the concrete topics underneath, while everything outside that pipeline (like a
+// module's own status topic) is left free to dead-letter if truly nobody else wants it.
+fn logger_handler(in_message: InMessage,out_tx: &Sender<OutMessage>) -> Result<(),Error> {
+
+    match in_message {
+
+        // logger does startup, subscribes to the whole frame/face pipeline
+        InMessage::Startup => {
+            println!("logger: Startup");
+            println!("logger: Subscribing to '/frames/**' and '/faces/**'");
+            send(out_tx,OutMessage::Subscribe("/frames/**".to_string()))?;
+            send(out_tx,OutMessage::Subscribe("/faces/**".to_string()))?;
+            Ok(())
+        },
+
+        // logger does shutdown
+        InMessage::Shutdown => {
+            println!("logger: Shutdown");
+            Ok(())
+        },
+
+        // logger observes all frame/face traffic flowing through the broker
+        InMessage::Generic(topic,_payload) => {
+            println!("logger: Observed traffic on '{}'",topic);
+            Ok(())
+        },
+
+        // and nothing else
+        _ => Ok(()),
+    }
+}
+
+// The health monitor watches the dead-letter topic, surfacing otherwise-silent pipeline bugs
+// (a publish with zero subscribers).
+fn health_monitor_handler(in_message: InMessage,out_tx: &Sender<OutMessage>) -> Result<(),Error> {
+
+    match in_message {
+
+        // health monitor does startup, subscribes to the dead-letter topic
+        InMessage::Startup => {
+            println!("health_monitor: Startup");
+            println!("health_monitor: Subscribing to '{}'",DEAD_LETTER_TOPIC);
+            send(out_tx,OutMessage::Subscribe(DEAD_LETTER_TOPIC.to_string()))?;
+            Ok(())
+        },
 
-                // and nothing else
-                _ => { },
+        // health monitor does shutdown
+        InMessage::Shutdown => {
+            println!("health_monitor: Shutdown");
+            Ok(())
+        },
+
+        // an undelivered message showed up
+        InMessage::Generic(_topic,payload) => {
+            if let Some(dead_letter) = payload.downcast_ref::<DeadLetter>() {
+                println!("health_monitor: '{}' was published with zero subscribers!",dead_letter.topic);
             }
+            Ok(())
         },
 
         // and nothing else
-        _ => { },
+        _ => Ok(()),
+    }
+}
+
+// An active module owns its thread's entire main loop (here simulating a blocking camera/RTSP
+// read) instead of being driven message-by-message by the broker. It checks for a pending
+// Shutdown between reads with a non-blocking `try_recv()` rather than ever calling `recv()`.
+fn rtsp_feed_active_loop(in_rx: &Receiver<InMessage>,out_tx: &Sender<OutMessage>) -> Result<(),Error> {
+
+    println!("rtsp_feed: Connecting to the RTSP stream...");
+
+    loop {
+
+        // non-blocking check: did the broker ask us to shut down?
+        match in_rx.try_recv() {
+            Ok(InMessage::Shutdown) => {
+                println!("rtsp_feed: Shutdown");
+                return Ok(());
+            },
+            Ok(_) => { },  // not interested in anything else right now
+            Err(TryRecvError::Empty) => { },
+            Err(TryRecvError::Disconnected) => return Ok(()),
+        }
+
+        // simulate a blocking read off the network
+        thread::sleep(Duration::from_millis(40));
+        println!("rtsp_feed: Read a frame off the wire, publishing it.");
+        send(out_tx,OutMessage::Generic("/frames/rtsp".to_string(),Arc::new(VideoFrame)))?;
+    }
+}
+
+// Wire format for the network bridge: a length-prefixed topic followed by a one-byte type tag
+// identifying the payload. Our demo payloads carry no fields of their own, so the tag alone is
+// enough to reconstruct them on the other side; a payload with real data would append its own
+// serialized body after the tag.
+fn payload_type_tag(payload: &Payload) -> Option<u8> {
+    if payload.downcast_ref::<VideoFrame>().is_some() {
+        Some(1)
+    } else if payload.downcast_ref::<FaceCoords>().is_some() {
+        Some(2)
+    } else if payload.downcast_ref::<CameraStatus>().is_some() {
+        Some(3)
+    } else {
+        None
+    }
+}
+
+fn payload_from_type_tag(tag: u8) -> Option<Payload> {
+    match tag {
+        1 => Some(Arc::new(VideoFrame)),
+        2 => Some(Arc::new(FaceCoords)),
+        3 => Some(Arc::new(CameraStatus)),
+        _ => None,
+    }
+}
+
+fn write_frame(stream: &mut TcpStream,topic: &str,tag: u8) -> io::Result<()> {
+    let topic_bytes = topic.as_bytes();
+    stream.write_all(&(topic_bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(topic_bytes)?;
+    stream.write_all(&[tag])?;
+    stream.flush()
+}
+
+// Pulls one complete frame off the front of `buf` if one has fully arrived yet, leaving any
+// trailing partial frame in place for the next call. `buf` accumulates raw socket reads, which
+// may stop anywhere mid-frame when the read timeout fires, so frames are only ever parsed once
+// all of their bytes are actually in hand.
+fn take_frame(buf: &mut Vec<u8>) -> Option<(String,u8)> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let topic_len = u32::from_be_bytes([buf[0],buf[1],buf[2],buf[3]]) as usize;
+    let frame_len = 4 + topic_len + 1;
+    if buf.len() < frame_len {
+        return None;
+    }
+    let topic = String::from_utf8_lossy(&buf[4..4 + topic_len]).into_owned();
+    let tag = buf[4 + topic_len];
+    buf.drain(0..frame_len);
+    Some((topic,tag))
+}
+
+// The remote broker this demo bridges to, and the topics relayed out across that link. A real
+// deployment would make both configurable (e.g. one kernel per host); this file hardcodes a demo
+// value like every other module here.
+const BRIDGE_REMOTE_ADDR: &str = "127.0.0.1:7878";
+const BRIDGE_RELAY_TOPICS: &[&str] = &["/frames/rtsp"];
+
+// The bridge relays locally-published topics to a remote broker over TCP, and mirrors whatever
+// the remote side sends back in as if it had been published locally. Like `rtsp_feed_active_loop`
+// it's an active module: it owns a blocking socket instead of reacting to one message at a time,
+// and polls `in_rx` non-blockingly for `Shutdown`. `suppress_echo` is the loop-prevention: a payload
+// we just injected from the remote side is recognized when it flows back through our own
+// subscription, and swallowed instead of being forwarded back out across the very link it came from.
+fn bridge_active_loop(in_rx: &Receiver<InMessage>,out_tx: &Sender<OutMessage>) -> Result<(),Error> {
+
+    println!("bridge: Connecting to remote broker at {}",BRIDGE_REMOTE_ADDR);
+    let remote_addr: SocketAddr = BRIDGE_REMOTE_ADDR.parse()
+        .map_err(|_| Error::new("bridge: invalid remote address"))?;
+    let mut stream = TcpStream::connect_timeout(&remote_addr,Duration::from_millis(500))
+        .map_err(|err| Error::new(format!("failed to connect to remote broker: {}",err)))?;
+    stream.set_read_timeout(Some(Duration::from_millis(20)))
+        .map_err(|err| Error::new(format!("failed to configure bridge socket: {}",err)))?;
+
+    println!("bridge: Connected, subscribing to relay topics locally");
+    for topic in BRIDGE_RELAY_TOPICS {
+        send(out_tx,OutMessage::Subscribe(topic.to_string()))?;
+    }
+
+    // the exact Arcs we've just injected from the remote side, awaiting the echo of each one
+    // coming back through our own subscription - matched by payload identity (Arc::ptr_eq), not
+    // by topic, so a genuinely new local publish on the same topic is never mistaken for an echo
+    let mut suppress_echo: Vec<Payload> = Vec::new();
+
+    // bytes read off the socket that don't yet add up to a complete frame; `read_exact` can't be
+    // used here since the read timeout may fire mid-frame, and re-parsing from a half-consumed
+    // stream next time would desync the session for good
+    let mut read_buf: Vec<u8> = Vec::new();
+    let mut read_chunk = [0u8;512];
+
+    loop {
+
+        match in_rx.try_recv() {
+            Ok(InMessage::Shutdown) => {
+                println!("bridge: Shutdown");
+                return Ok(());
+            },
+            Ok(InMessage::Generic(topic,payload)) => {
+                if let Some(echo_index) = suppress_echo.iter().position(|injected| Arc::ptr_eq(injected,&payload)) {
+                    suppress_echo.remove(echo_index);  // the echo of what we just injected - swallow it
+                } else if let Some(tag) = payload_type_tag(&payload) {
+                    write_frame(&mut stream,&topic,tag)
+                        .map_err(|err| Error::new(format!("lost connection to remote broker while sending: {}",err)))?;
+                    println!("bridge: Relayed '{}' out to the remote broker",topic);
+                }
+            },
+            Ok(_) => { },  // not interested in anything else right now
+            Err(TryRecvError::Empty) => { },
+            Err(TryRecvError::Disconnected) => return Ok(()),
+        }
+
+        match stream.read(&mut read_chunk) {
+            Ok(0) => return Err(Error::new("remote broker closed the connection")),
+            Ok(n) => read_buf.extend_from_slice(&read_chunk[..n]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => { },
+            Err(err) => return Err(Error::new(format!("lost connection to remote broker while reading: {}",err))),
+        }
+
+        while let Some((topic,tag)) = take_frame(&mut read_buf) {
+            if let Some(payload) = payload_from_type_tag(tag) {
+                println!("bridge: Relaying '{}' in from the remote broker",topic);
+                suppress_echo.push(payload.clone());
+                send(out_tx,OutMessage::Generic(topic,payload))?;
+            }
+        }
     }
 }
 
@@ -241,14 +750,68 @@ fn main() {
     // and why not another one
     modules.push(Module::new("OtherDisplay",display_handler));
 
-    // endless loop
+    // observe all frame/face traffic without knowing the concrete topic names in advance
+    modules.push(Module::new("Logger",logger_handler));
+
+    // surface otherwise-silent pipeline bugs (publishes with zero subscribers)
+    modules.push(Module::new("HealthMonitor",health_monitor_handler));
+
+    // an active module: owns its whole thread loop instead of reacting to one message at a time
+    modules.push(Module::new_active("RtspFeed",rtsp_feed_active_loop));
+
+    // relays '/frames/rtsp' to a remote broker over TCP; with no peer listening in this demo it
+    // simply fails to connect and reports the error instead of panicking, same as any other
+    // module whose hardware/resource initialization can fail
+    modules.push(Module::new_active("Bridge",bridge_active_loop));
+
+    // let every module initialize itself (subscribe to topics, arm timers, etc.)
+    for module in &modules {
+        notify(&module.in_tx,&module.name,InMessage::Startup);
+    }
+
+    // min-heap of (deadline,module index,generation), nearest deadline first. The generation is
+    // the module's tick_generation at the time this entry was scheduled, so a SetTimer that
+    // re-arms a module's rate invalidates every entry scheduled by an earlier SetTimer instead of
+    // letting both old and new reschedule chains run forever side by side.
+    let mut timers: BinaryHeap<Reverse<(Instant,usize,u64)>> = BinaryHeap::new();
+
+    // single-slot mailbox per (module index,topic) for conflated ("keep-latest") subscriptions
+    let mut latest_mailbox: HashMap<(usize,String),Payload> = HashMap::new();
+
+    // outstanding requests: correlation_id -> the requester's own sender, so a reply (or a
+    // timeout) is routed back only to whoever asked, never broadcast
+    let mut pending_requests: HashMap<u64,Sender<InMessage>> = HashMap::new();
+    let mut request_deadlines: BinaryHeap<Reverse<(Instant,u64)>> = BinaryHeap::new();
+
+    // every concrete (non-wildcard) topic anyone has ever subscribed to, and the subscriber
+    // count we last told everyone about, so publishers can be notified when it changes
+    let mut known_topics: HashSet<String> = HashSet::new();
+    let mut last_subscriber_counts: HashMap<String,usize> = HashMap::new();
+
+    // ticks delivered so far, across all modules; once this demo has run long enough we shut
+    // down cleanly instead of running forever
+    let mut total_ticks_delivered: usize = 0;
+
     loop {
 
-        // This logic needs to improve. There should be a timer here also...
+        // enough of the demo has run; tear everything down instead of looping forever
+        if total_ticks_delivered >= 30 {
+            for module in &modules {
+                let _ = module.in_tx.send(InMessage::Shutdown);
+            }
+            for module in &modules {
+                if let Some(handle) = module.handle.borrow_mut().take() {
+                    let _ = handle.join();
+                }
+            }
+            println!("broker: all modules shut down, exiting");
+            break;
+        }
+
         let mut did_something = false;
 
         // for each module
-        for module in &modules {
+        for (index,module) in modules.iter().enumerate() {
 
             // flush incoming messages, if any
             while let Ok(out_message) = module.out_rx.try_recv() {
@@ -257,33 +820,155 @@ fn main() {
 
                 match out_message {
 
-                    // module wants to subscribe to a topic
+                    // module wants to subscribe to a topic (pattern, possibly with '*'/'**' wildcards)
                     OutMessage::Subscribe(topic) => {
-                        module.subscriptions.borrow_mut().insert(topic);
+                        if !topic.contains('*') {
+                            known_topics.insert(topic.clone());
+                        }
+                        let subscription = Subscription { pattern: TopicPattern::parse(&topic),conflated: false };
+                        module.subscriptions.borrow_mut().insert(topic,subscription);
+                    },
+
+                    // module wants to subscribe, keeping only the latest undelivered message per topic
+                    OutMessage::SubscribeLatest(topic) => {
+                        if !topic.contains('*') {
+                            known_topics.insert(topic.clone());
+                        }
+                        let subscription = Subscription { pattern: TopicPattern::parse(&topic),conflated: true };
+                        module.subscriptions.borrow_mut().insert(topic,subscription);
                     },
 
-                    // module wants to unsubscribe from a topic
+                    // module wants to unsubscribe from a topic (pattern)
                     OutMessage::Unsubscribe(topic) => {
                         module.subscriptions.borrow_mut().remove(&topic);
                     },
 
-                    // module sends message to a topic
-                    OutMessage::Generic(topic,app_message) => {
+                    // module sends a payload to a topic
+                    OutMessage::Generic(topic,payload) => {
+                        let delivered = route(&modules,&mut latest_mailbox,&topic,&payload);
+
+                        // nobody was listening, so wrap it up and republish it on the dead-letter topic
+                        if !delivered && topic != DEAD_LETTER_TOPIC {
+                            let dead_letter: Payload = Arc::new(DeadLetter { topic: topic.clone(),payload: payload.clone() });
+                            route(&modules,&mut latest_mailbox,DEAD_LETTER_TOPIC,&dead_letter);
+                        }
+                    },
+
+                    // module wants to be ticked at a fixed rate from now on; bump the generation
+                    // so any entry still on the heap from an earlier SetTimer is recognized as
+                    // stale and dropped instead of spawning its own parallel reschedule chain
+                    OutMessage::SetTimer(interval) => {
+                        *module.tick_interval.borrow_mut() = Some(interval);
+                        let generation = module.tick_generation.get() + 1;
+                        module.tick_generation.set(generation);
+                        timers.push(Reverse((Instant::now() + interval,index,generation)));
+                    },
+
+                    // module calls whoever serves this topic, expecting a single reply
+                    OutMessage::Request(topic,payload,correlation_id) => {
+                        pending_requests.insert(correlation_id,module.in_tx.clone());
+                        request_deadlines.push(Reverse((Instant::now() + REQUEST_TIMEOUT,correlation_id)));
                         for target in &modules {
-                            if target.subscriptions.borrow_mut().contains(&topic) {
-                                target.in_tx.send(
-                                    InMessage::Generic(topic.clone(),app_message.clone())
-                                ).expect(&format!("broker: failed to send AppMessage to module '{}'",target.name));
+                            let subscribed = target.subscriptions.borrow().values().any(|s| s.pattern.matches(&topic));
+                            if subscribed {
+                                notify(&target.in_tx,&target.name,InMessage::Request(topic.clone(),payload.clone(),correlation_id));
                             }
                         }
                     },
+
+                    // module answers a Request it previously received, route it back to the requester only
+                    OutMessage::Reply(correlation_id,payload) => {
+                        if let Some(requester_tx) = pending_requests.remove(&correlation_id) {
+                            // the requester's thread may have exited in the meantime; nothing to do then
+                            let _ = requester_tx.send(InMessage::Reply(correlation_id,payload));
+                        }
+                        // otherwise the request already timed out (or the id is unknown); drop it silently
+                    },
+
+                    // a lifecycle hook failed; the broker (not just the module's own thread) now
+                    // knows about it, so log it and shut the module down cleanly instead of
+                    // leaving it running half-initialized and invisible to the broker
+                    OutMessage::Failed(reason) => {
+                        println!("broker: module '{}' reported a failure: {}",module.name,reason);
+                        notify(&module.in_tx,&module.name,InMessage::Shutdown);
+                    },
                 }
             }
         }
 
-        // if no messages were passed, add a tiny wait here
+        // pop and deliver every timer that's due by now, rescheduling each for its next tick
+        let now = Instant::now();
+        while let Some(&Reverse((deadline,index,generation))) = timers.peek() {
+            if deadline > now {
+                break;
+            }
+            timers.pop();
+
+            let module = &modules[index];
+
+            // this entry was superseded by a later SetTimer on the same module; it's not the
+            // current schedule, so drop it instead of delivering a Tick and reschedule at the rate
+            if generation != module.tick_generation.get() {
+                continue;
+            }
+
+            did_something = true;
+            total_ticks_delivered += 1;
+            notify(&module.in_tx,&module.name,InMessage::Tick);
+
+            // only reschedule if the module hasn't cleared its timer since
+            if let Some(interval) = *module.tick_interval.borrow() {
+                timers.push(Reverse((deadline + interval,index,generation)));
+            }
+        }
+
+        // give up on any request whose deadline passed without a reply, and tell the requester
+        while let Some(&Reverse((deadline,correlation_id))) = request_deadlines.peek() {
+            if deadline > now {
+                break;
+            }
+            request_deadlines.pop();
+
+            // if it's still pending the reply never arrived in time; if not, it was already answered
+            if let Some(requester_tx) = pending_requests.remove(&correlation_id) {
+                did_something = true;
+                let _ = requester_tx.send(InMessage::RequestTimedOut(correlation_id));
+            }
+        }
+
+        // tell every module about subscriber-count changes on any topic that's ever been subscribed to
+        for topic in &known_topics {
+            let count = modules.iter()
+                .filter(|m| m.subscriptions.borrow().values().any(|s| s.pattern.matches(topic)))
+                .count();
+            if last_subscriber_counts.get(topic).copied() != Some(count) {
+                last_subscriber_counts.insert(topic.clone(),count);
+                did_something = true;
+                for module in &modules {
+                    notify(&module.in_tx,&module.name,InMessage::SubscriberCountChanged(topic.clone(),count));
+                }
+            }
+        }
+
+        // deliver one conflated message per topic to every module that's ready for it, dropping
+        // everything older that was overwritten while the module was still busy
+        latest_mailbox.retain(|&(index,ref topic),payload| {
+            let module = &modules[index];
+            if !module.ready.load(Ordering::SeqCst) {
+                return true;
+            }
+            did_something = true;
+            notify(&module.in_tx,&module.name,InMessage::Generic(topic.clone(),payload.clone()));
+            false
+        });
+
+        // if nothing happened, sleep exactly until the nearest due timer (or a fallback poll rate)
         if !did_something {
-            thread::sleep(Duration::from_millis(100));
+            let sleep_duration = match timers.peek() {
+                Some(&Reverse((deadline,_,_))) => deadline.saturating_duration_since(Instant::now()),
+                None => Duration::from_millis(100),
+            };
+            thread::sleep(sleep_duration);
         }
     }
 }